@@ -72,6 +72,10 @@ use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
 
+use directories::ProjectDirs;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
 #[allow(dead_code)]
 fn read_file(file_path: &str) -> Result<String, Box<dyn Error>> {
     let mut fd = File::open(file_path)?;
@@ -108,7 +112,6 @@ pub mod machine_id {
 pub mod machine_id {
     use super::read_file;
     use std::error::Error;
-    use std::process::Command;
 
     const HOST_ID_PATH: &str = "/etc/hostid";
 
@@ -120,61 +123,171 @@ pub mod machine_id {
         }
     }
 
+    // Read `smbios.system.uuid` through the kenv(2) syscall instead of spawning
+    // the `kenv` binary, so the lookup works under sandboxes that forbid fork/exec.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    fn read_from_kenv() -> Result<String, Box<dyn Error>> {
+        use std::ffi::{CStr, CString};
+
+        const KENV_GET: libc::c_int = 0;
+        let name = CString::new("smbios.system.uuid")?;
+        let mut buf = [0 as libc::c_char; 128];
+
+        let n = unsafe {
+            libc::kenv(
+                KENV_GET,
+                name.as_ptr(),
+                buf.as_mut_ptr(),
+                buf.len() as libc::c_int,
+            )
+        };
+        if n < 0 {
+            return Err(From::from("kenv(2) lookup of smbios.system.uuid failed"));
+        }
+
+        let value = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        Ok(value.to_string_lossy().trim().to_string())
+    }
+
+    #[cfg(not(any(target_os = "freebsd", target_os = "dragonfly")))]
     fn read_from_kenv() -> Result<String, Box<dyn Error>> {
-        let output = Command::new("kenv")
-            .args(&["-q", "smbios.system.uuid"])
-            .output()?;
-        let content = String::from_utf8_lossy(&output.stdout);
-        Ok(content.trim().to_string())
+        Err(From::from("kenv(2) is not available on this platform"))
     }
 }
 
 #[cfg(target_os = "macos")]
+#[allow(non_upper_case_globals, non_snake_case, non_camel_case_types)]
 mod machine_id {
-    // machineID returns the uuid returned by `ioreg -rd1 -c IOPlatformExpertDevice`.
+    // machineID reads the IOPlatformUUID property of the IOPlatformExpertDevice
+    // directly through IOKit, avoiding a spawn of `ioreg` and the string-scraping
+    // of its output (which breaks under the App Sandbox / seccomp).
     use std::error::Error;
-    use std::process::Command;
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_void};
+
+    type CFAllocatorRef = *const c_void;
+    type CFTypeRef = *const c_void;
+    type CFStringRef = *const c_void;
+    type io_object_t = u32;
+    type io_registry_entry_t = io_object_t;
+    type mach_port_t = u32;
+    type kern_return_t = i32;
+    type CFStringEncoding = u32;
+    type CFIndex = isize;
+    type Boolean = u8;
+
+    const kCFStringEncodingUTF8: CFStringEncoding = 0x0800_0100;
+    const kIOMainPortNull: mach_port_t = 0;
+
+    #[link(name = "IOKit", kind = "framework")]
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFAllocatorDefault: CFAllocatorRef;
+        fn IOServiceMatching(name: *const c_char) -> *mut c_void;
+        fn IOServiceGetMatchingService(mainPort: mach_port_t, matching: *mut c_void) -> io_object_t;
+        fn IORegistryEntryCreateCFProperty(
+            entry: io_registry_entry_t,
+            key: CFStringRef,
+            allocator: CFAllocatorRef,
+            options: u32,
+        ) -> CFTypeRef;
+        fn IOObjectRelease(object: io_object_t) -> kern_return_t;
+        fn CFStringCreateWithCString(
+            alloc: CFAllocatorRef,
+            cStr: *const c_char,
+            encoding: CFStringEncoding,
+        ) -> CFStringRef;
+        fn CFStringGetCStringPtr(theString: CFStringRef, encoding: CFStringEncoding)
+            -> *const c_char;
+        fn CFStringGetCString(
+            theString: CFStringRef,
+            buffer: *mut c_char,
+            bufferSize: CFIndex,
+            encoding: CFStringEncoding,
+        ) -> Boolean;
+        fn CFStringGetLength(theString: CFStringRef) -> CFIndex;
+        fn CFRelease(cf: CFTypeRef);
+    }
 
     /// Return machine id
     pub fn get_machine_id() -> Result<String, Box<dyn Error>> {
-        let output = Command::new("ioreg")
-            .args(&["-rd1", "-c", "IOPlatformExpertDevice"])
-            .output()?;
-        let content = String::from_utf8_lossy(&output.stdout);
-        extract_id(&content)
-    }
-
-    fn extract_id(content: &str) -> Result<String, Box<dyn Error>> {
-        let lines = content.split('\n');
-        for line in lines {
-            if line.contains("IOPlatformUUID") {
-                let k: Vec<&str> = line.rsplitn(2, '=').collect();
-                let id = k[0].trim_matches(|c: char| c == '"' || c.is_whitespace());
-                return Ok(id.to_string());
+        unsafe {
+            let matching = IOServiceMatching(b"IOPlatformExpertDevice\0".as_ptr() as *const c_char);
+            if matching.is_null() {
+                return Err(From::from("IOServiceMatching(IOPlatformExpertDevice) failed"));
+            }
+
+            // IOServiceGetMatchingService consumes the reference on `matching`.
+            let service = IOServiceGetMatchingService(kIOMainPortNull, matching);
+            if service == 0 {
+                return Err(From::from("no matching IOPlatformExpertDevice service"));
+            }
+
+            let key = CFStringCreateWithCString(
+                kCFAllocatorDefault,
+                b"IOPlatformUUID\0".as_ptr() as *const c_char,
+                kCFStringEncodingUTF8,
+            );
+            let property =
+                IORegistryEntryCreateCFProperty(service, key, kCFAllocatorDefault, 0);
+            CFRelease(key);
+            IOObjectRelease(service);
+
+            if property.is_null() {
+                return Err(From::from("IOPlatformUUID property not found"));
             }
+
+            let result = cfstring_to_string(property);
+            CFRelease(property);
+            result
         }
-        Err(From::from(
-            "No matching IOPlatformUUID in `ioreg -rd1 -c IOPlatformExpertDevice` command.",
-        ))
+    }
+
+    unsafe fn cfstring_to_string(s: CFStringRef) -> Result<String, Box<dyn Error>> {
+        // Fast path: the backing store is already a UTF-8 C string.
+        let ptr = CFStringGetCStringPtr(s, kCFStringEncodingUTF8);
+        if !ptr.is_null() {
+            return Ok(CStr::from_ptr(ptr).to_string_lossy().into_owned());
+        }
+
+        let len = CFStringGetLength(s);
+        let capacity = len * 4 + 1;
+        let mut buf = vec![0 as c_char; capacity as usize];
+        if CFStringGetCString(s, buf.as_mut_ptr(), capacity, kCFStringEncodingUTF8) == 0 {
+            return Err(From::from("failed to decode IOPlatformUUID CFString"));
+        }
+        Ok(CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned())
     }
 }
 
 #[cfg(target_os = "windows")]
 pub mod machine_id {
     use std::error::Error;
-    use std::ffi::c_int;
     use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WOW64_64KEY};
     use winreg::RegKey;
 
-    extern "C" {
-        fn MachineUidIsWow64() -> c_int;
-    }
+    use windows_sys::Win32::Foundation::FALSE;
+    use windows_sys::Win32::System::SystemInformation::{
+        GetSystemFirmwareTable, IMAGE_FILE_MACHINE_UNKNOWN,
+    };
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, IsWow64Process, IsWow64Process2,
+    };
 
     /// Return machine id
     pub fn get_machine_id() -> Result<String, Box<dyn Error>> {
+        match read_machine_guid() {
+            Ok(id) if !id.is_empty() => Ok(id),
+            // MachineGuid can be absent or blank on stripped or imaged installs;
+            // fall back to the firmware (SMBIOS) system UUID.
+            _ => read_smbios_uuid(),
+        }
+    }
+
+    fn read_machine_guid() -> Result<String, Box<dyn Error>> {
         let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
 
-        let flag = if unsafe { MachineUidIsWow64() == 1 } && cfg!(target_pointer_width = "32") {
+        let flag = if is_wow64() && cfg!(target_pointer_width = "32") {
             KEY_READ | KEY_WOW64_64KEY
         } else {
             KEY_READ
@@ -185,6 +298,95 @@ pub mod machine_id {
 
         Ok(id.trim().to_string())
     }
+
+    // Decide whether this 32-bit process runs under WOW64, preferring the modern
+    // IsWow64Process2 and falling back to IsWow64Process when that call fails.
+    fn is_wow64() -> bool {
+        unsafe {
+            let process = GetCurrentProcess();
+
+            let mut process_machine = 0u16;
+            let mut native_machine = 0u16;
+            if IsWow64Process2(process, &mut process_machine, &mut native_machine) != FALSE {
+                return process_machine != IMAGE_FILE_MACHINE_UNKNOWN;
+            }
+
+            let mut wow64 = FALSE;
+            if IsWow64Process(process, &mut wow64) != FALSE {
+                return wow64 != FALSE;
+            }
+
+            false
+        }
+    }
+
+    fn read_smbios_uuid() -> Result<String, Box<dyn Error>> {
+        // 'RSMB' — the raw SMBIOS firmware table provider.
+        const RSMB: u32 = u32::from_be_bytes(*b"RSMB");
+
+        unsafe {
+            let size = GetSystemFirmwareTable(RSMB, 0, std::ptr::null_mut(), 0);
+            if size == 0 {
+                return Err(From::from("GetSystemFirmwareTable(RSMB) returned no data"));
+            }
+
+            let mut buf = vec![0u8; size as usize];
+            let written = GetSystemFirmwareTable(RSMB, 0, buf.as_mut_ptr().cast(), size);
+            if written == 0 || written > size {
+                return Err(From::from("failed to read the SMBIOS firmware table"));
+            }
+            buf.truncate(written as usize);
+
+            parse_smbios_uuid(&buf)
+        }
+    }
+
+    // Walk the DMI structures and return the UUID of the System Information
+    // (type 1) record. The raw buffer is prefixed by an 8-byte RawSMBIOSData
+    // header, after which each structure has a formatted area followed by a
+    // string-set terminated by a double NUL.
+    fn parse_smbios_uuid(data: &[u8]) -> Result<String, Box<dyn Error>> {
+        if data.len() <= 8 {
+            return Err(From::from("SMBIOS table too small"));
+        }
+        let table = &data[8..];
+
+        let mut i = 0usize;
+        while i + 4 <= table.len() {
+            let struct_type = table[i];
+            let length = table[i + 1] as usize;
+            if length < 4 || i + length > table.len() {
+                break;
+            }
+
+            if struct_type == 1 && i + 8 + 16 <= table.len() {
+                return Ok(format_smbios_uuid(&table[i + 8..i + 8 + 16]));
+            }
+
+            let mut j = i + length;
+            while j + 1 < table.len() && !(table[j] == 0 && table[j + 1] == 0) {
+                j += 1;
+            }
+            i = j + 2;
+        }
+
+        Err(From::from(
+            "no System Information (type 1) UUID in the SMBIOS table",
+        ))
+    }
+
+    // The first three UUID fields are stored little-endian per SMBIOS 2.6+;
+    // render them in the same byte order the Windows tooling displays.
+    fn format_smbios_uuid(b: &[u8]) -> String {
+        format!(
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0],
+            b[5], b[4],
+            b[7], b[6],
+            b[8], b[9],
+            b[10], b[11], b[12], b[13], b[14], b[15],
+        )
+    }
 }
 
 #[cfg(target_os = "illumos")]
@@ -198,3 +400,320 @@ pub mod machine_id {
 }
 
 pub use machine_id::get_machine_id as get;
+
+// Fold an arbitrary platform id string into the raw 16-byte value it represents.
+// Hex sources (Linux, illumos, dashed UUIDs once the separators are stripped) are
+// decoded directly and zero-extended when narrower than 128 bits; anything that is
+// not clean hex is hashed down to 16 bytes so every source yields a stable key.
+fn id_to_bytes(raw: &str) -> [u8; 16] {
+    let cleaned: String = raw
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    if cleaned.bytes().all(|b| b.is_ascii_hexdigit()) {
+        // Left-pad odd-length hex (and treat empty as all-zero) so narrow sources
+        // such as illumos's `gethostid` hex integer zero-extend deterministically
+        // rather than falling through to the hash branch.
+        let padded = if cleaned.len().is_multiple_of(2) {
+            cleaned
+        } else {
+            format!("0{}", cleaned)
+        };
+
+        let decoded: Vec<u8> = padded
+            .as_bytes()
+            .chunks(2)
+            .map(|c| (hex_val(c[0]) << 4) | hex_val(c[1]))
+            .collect();
+
+        let mut out = [0u8; 16];
+        if decoded.len() >= 16 {
+            out.copy_from_slice(&decoded[decoded.len() - 16..]);
+        } else {
+            // zero-extend narrow sources (e.g. a 32-bit illumos hostid) on the left.
+            out[16 - decoded.len()..].copy_from_slice(&decoded);
+        }
+        out
+    } else {
+        let digest = Sha256::digest(cleaned.as_bytes());
+        let mut out = [0u8; 16];
+        out.copy_from_slice(&digest[..16]);
+        out
+    }
+}
+
+fn hex_val(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        b'a'..=b'f' => b - b'a' + 10,
+        _ => 0,
+    }
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let h: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &h[0..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..32]
+    )
+}
+
+/// A platform-independent, canonicalized machine id.
+///
+/// Every supported source — Linux's 32 hex chars, a macOS/Windows dashed UUID,
+/// illumos's variable-width `gethostid` integer — is folded into a single
+/// 128-bit value so ids can be compared across platforms without bespoke
+/// parsing. Sources narrower than 128 bits are zero-extended deterministically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MachineId([u8; 16]);
+
+impl MachineId {
+    /// The canonicalized id as a raw 16-byte value.
+    pub fn as_bytes(&self) -> [u8; 16] {
+        self.0
+    }
+
+    /// The id as 32 lowercase hex characters, matching Linux's `machine-id`.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The id as a dashed, lowercase UUID string.
+    pub fn to_uuid_string(&self) -> String {
+        format_uuid(&self.0)
+    }
+}
+
+impl std::fmt::Display for MachineId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Return the native machine id as a canonicalized [`MachineId`].
+pub fn get_id() -> Result<MachineId, Box<dyn Error>> {
+    Ok(MachineId(id_to_bytes(&get()?)))
+}
+
+/// Builder for [`get_or_create`](Builder::get_or_create).
+///
+/// The qualifier/organization/application triple and the filename select where a
+/// generated id is persisted, following the platform conventions exposed by the
+/// `directories` crate (e.g. `~/.config/<application>/machine-id` on Linux).
+pub struct Builder {
+    qualifier: String,
+    organization: String,
+    application: String,
+    filename: String,
+}
+
+impl Builder {
+    /// Create a builder with the default directory and filename.
+    pub fn new() -> Self {
+        Builder {
+            qualifier: String::new(),
+            organization: String::new(),
+            application: "machine-uid".to_string(),
+            filename: "machine-id".to_string(),
+        }
+    }
+
+    /// Set the reverse-domain qualifier (used for the macOS bundle path).
+    pub fn qualifier(mut self, qualifier: &str) -> Self {
+        self.qualifier = qualifier.to_string();
+        self
+    }
+
+    /// Set the organization name used for the config directory.
+    pub fn organization(mut self, organization: &str) -> Self {
+        self.organization = organization.to_string();
+        self
+    }
+
+    /// Set the application name used for the config directory.
+    pub fn application(mut self, application: &str) -> Self {
+        self.application = application.to_string();
+        self
+    }
+
+    /// Set the filename the generated id is persisted under.
+    pub fn filename(mut self, filename: &str) -> Self {
+        self.filename = filename.to_string();
+        self
+    }
+
+    /// Return the native machine id, or a generated-and-persisted one.
+    ///
+    /// If no native id can be found, a random 128-bit id is generated, written to
+    /// the configured config directory, and returned on every subsequent call.
+    pub fn get_or_create(&self) -> Result<MachineId, Box<dyn Error>> {
+        // An empty platform source (e.g. a present-but-blank `/etc/machine-id`)
+        // canonicalizes to the all-zero id; treat that as "not found" so the
+        // persisted fallback still runs.
+        if let Ok(id) = get_id() {
+            if id.as_bytes() != [0u8; 16] {
+                return Ok(id);
+            }
+        }
+
+        let dirs = ProjectDirs::from(&self.qualifier, &self.organization, &self.application)
+            .ok_or("could not determine a config directory for the machine id")?;
+        let path = dirs.config_dir().join(&self.filename);
+
+        if let Ok(existing) = std::fs::read_to_string(&path) {
+            let existing = existing.trim();
+            if !existing.is_empty() {
+                return Ok(MachineId(id_to_bytes(existing)));
+            }
+        }
+
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(|e| format!("getrandom failed: {e}"))?;
+        let id = MachineId(bytes);
+
+        std::fs::create_dir_all(dirs.config_dir())?;
+        std::fs::write(&path, id.to_hex())?;
+
+        Ok(id)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
+}
+
+/// Return the native machine id, or a generated-and-persisted one under the
+/// default config directory. See [`Builder`] to customize the location.
+pub fn get_or_create() -> Result<MachineId, Box<dyn Error>> {
+    Builder::new().get_or_create()
+}
+
+/// Return an application-specific machine id derived from the native machine id.
+///
+/// This mirrors systemd's `sd_id128_get_machine_app_specific`: the raw 16-byte
+/// machine id keys an HMAC-SHA256 over the 16-byte application id, and the first
+/// 16 bytes of the MAC are reshaped into a v4-style UUID. Identical
+/// `(machine, app_id)` pairs always map to the same value and distinct apps
+/// diverge, while the raw machine id cannot be recovered from the output — so the
+/// result is safe to publish in untrusted environments.
+pub fn get_app_specific(app_id: &str) -> Result<String, Box<dyn Error>> {
+    Ok(derive_app_specific(&get_id()?.as_bytes(), app_id))
+}
+
+fn derive_app_specific(machine: &[u8; 16], app_id: &str) -> String {
+    // A UUID-shaped app id is used as its 16 raw bytes; any other string is hashed
+    // exactly as given, so two such strings that differ only in case, dashes or
+    // leading zeros still derive different values.
+    let undashed: String = app_id.chars().filter(|c| *c != '-').collect();
+    let app = if undashed.len() == 32 && undashed.bytes().all(|b| b.is_ascii_hexdigit()) {
+        id_to_bytes(&undashed)
+    } else {
+        let digest = Sha256::digest(app_id.as_bytes());
+        let mut out = [0u8; 16];
+        out.copy_from_slice(&digest[..16]);
+        out
+    };
+
+    // HMAC-SHA256 accepts a key of any length, so construction cannot fail here.
+    let mut mac = Hmac::<Sha256>::new_from_slice(machine).expect("HMAC accepts any key length");
+    mac.update(&app);
+    let mac = mac.finalize().into_bytes();
+
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&mac[..16]);
+    // Clear and set the UUID version (4) and variant (RFC 4122) bits.
+    out[6] = (out[6] & 0x0f) | 0x40;
+    out[8] = (out[8] & 0x3f) | 0x80;
+
+    format_uuid(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINUX_ID: &str = "d5181d7a6d3f4f8a9b0c1d2e3f405162";
+
+    #[test]
+    fn linux_id_round_trips() {
+        let id = MachineId(id_to_bytes(LINUX_ID));
+        assert_eq!(id.to_hex(), LINUX_ID);
+        assert_eq!(id.to_uuid_string(), "d5181d7a-6d3f-4f8a-9b0c-1d2e3f405162");
+    }
+
+    #[test]
+    fn dashed_uuid_strips_to_same_value() {
+        let dashed = "D5181D7A-6D3F-4F8A-9B0C-1D2E3F405162";
+        assert_eq!(id_to_bytes(dashed), id_to_bytes(LINUX_ID));
+    }
+
+    #[test]
+    fn narrow_hex_is_left_zero_extended() {
+        // An illumos `gethostid` integer keeps its low bytes and zero-extends.
+        assert_eq!(
+            id_to_bytes("a1b2c3d4"),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xa1, 0xb2, 0xc3, 0xd4]
+        );
+    }
+
+    #[test]
+    fn odd_length_hex_is_padded_not_hashed() {
+        // "b" left-pads to "0b", a single byte, rather than being folded through SHA-256.
+        let id = id_to_bytes("b");
+        assert_eq!(id[15], 0x0b);
+        assert!(id[..15].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn empty_id_is_all_zero() {
+        assert_eq!(id_to_bytes(""), [0u8; 16]);
+    }
+
+    #[test]
+    fn format_uuid_groups_bytes() {
+        let bytes = [
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ];
+        assert_eq!(format_uuid(&bytes), "01234567-89ab-cdef-0123-456789abcdef");
+    }
+
+    #[test]
+    fn app_specific_is_deterministic() {
+        let machine = id_to_bytes(LINUX_ID);
+        assert_eq!(
+            derive_app_specific(&machine, "com.example.app"),
+            derive_app_specific(&machine, "com.example.app")
+        );
+    }
+
+    #[test]
+    fn app_specific_diverges_per_app() {
+        let machine = id_to_bytes(LINUX_ID);
+        assert_ne!(
+            derive_app_specific(&machine, "com.example.one"),
+            derive_app_specific(&machine, "com.example.two")
+        );
+    }
+
+    #[test]
+    fn app_specific_is_v4_shaped() {
+        let out = derive_app_specific(&id_to_bytes(LINUX_ID), "com.example.app");
+        assert_eq!(out.as_bytes()[14], b'4');
+        assert!(matches!(out.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+    }
+
+    #[test]
+    fn app_specific_does_not_leak_machine_id() {
+        let out = derive_app_specific(&id_to_bytes(LINUX_ID), "com.example.app");
+        assert!(!out.replace('-', "").contains(LINUX_ID));
+    }
+}